@@ -0,0 +1,849 @@
+//! Shared steganography core.
+//!
+//! `CharacterEncoding`, `Channel`, `EncodeConfig` and the header/bit logic
+//! used to be copy-pasted between the Neon addon and the wasm-bindgen build,
+//! and the two copies had already started to drift. This crate factors that
+//! logic into one `Encoder`/`Decoder` pair so both FFI surfaces, and the
+//! plain CLI binary, call the same code.
+
+use core::fmt;
+use std::borrow::Cow;
+use std::io::{Read, Write};
+use std::sync::OnceLock;
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use image::{Pixel, RgbaImage};
+
+/// Set on the header's encoding-tag byte when the payload was shrunk by
+/// `compress` before embedding. None of the `CharacterEncoding` tag values
+/// use this bit, so it rides alongside the tag in the same byte.
+const COMPRESSED_FLAG: u8 = 0x80;
+
+pub struct EncodeConfig {
+  pub ignore_alpha: bool,
+  pub ignore_white_pixels: bool,
+  pub ignore_black_pixels: bool,
+  pub debug: bool
+}
+
+/// Everything that can go wrong while embedding or extracting a message,
+/// in place of the `panic!`s this crate used to raise across what is, for
+/// the Neon and wasm-bindgen builds, an FFI boundary.
+///
+/// Marked `#[non_exhaustive]` so new failure modes (varint overflow,
+/// compression, payload-kind tags, ...) can be added without breaking
+/// downstream `match`es.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum StegError {
+  Io(String),
+  InvalidChannelIndex(usize),
+  UnknownEncodingTag(u8),
+  BadBitVectorLength,
+  CapacityExceeded { needed: usize, available: usize },
+  EncodingMismatch,
+  TruncatedPayload,
+  InvalidUtf8,
+  InvalidUtf16,
+  InvalidUtf32,
+  CrcMismatch { crc_val: u32, crc_sum: u32 },
+  DecompressionFailed(String),
+  VarintOverflow
+}
+
+impl fmt::Display for StegError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      StegError::Io(message) => write!(f, "I/O error: {}", message),
+      StegError::InvalidChannelIndex(i) => write!(f, "invalid channel index: {}", i),
+      StegError::UnknownEncodingTag(tag) => write!(f, "unknown character encoding tag: 0x{:x}", tag),
+      StegError::BadBitVectorLength => write!(f, "bit vector does not have the expected length"),
+      StegError::CapacityExceeded { needed, available } =>
+        write!(f, "message needs {} bits but the image only has {} bits of capacity", needed, available),
+      StegError::EncodingMismatch => write!(f, "the encoding of the message does not match the requested encoding"),
+      StegError::TruncatedPayload => write!(f, "carrier image ran out of pixels before the full payload was read"),
+      StegError::InvalidUtf8 => write!(f, "decoded payload is not valid UTF-8"),
+      StegError::InvalidUtf16 => write!(f, "decoded payload is not valid UTF-16"),
+      StegError::InvalidUtf32 => write!(f, "decoded payload contains a code point that is not valid UTF-32"),
+      StegError::CrcMismatch { crc_val, crc_sum } =>
+        write!(f, "CRC mismatch (stored=0x{:08x}, computed=0x{:08x})", crc_val, crc_sum),
+      StegError::DecompressionFailed(message) => write!(f, "failed to inflate compressed payload: {}", message),
+      StegError::VarintOverflow => write!(f, "length varint ran past 5 groups without a terminator (would overflow u32)")
+    }
+  }
+}
+
+impl std::error::Error for StegError {}
+
+pub enum Channel {
+  Red,
+  Green,
+  Blue,
+  Alpha
+}
+
+impl Channel {
+  pub fn new(i: usize) -> Result<Channel, StegError> {
+    match i {
+      0 => Ok(Channel::Red),
+      1 => Ok(Channel::Green),
+      2 => Ok(Channel::Blue),
+      3 => Ok(Channel::Alpha),
+      _ => Err(StegError::InvalidChannelIndex(i))
+    }
+  }
+
+  pub fn name(&self) -> &str {
+    match self {
+      Channel::Red => "Red",
+      Channel::Green => "Green",
+      Channel::Blue => "Blue",
+      Channel::Alpha => "Alpha"
+    }
+  }
+}
+
+#[repr(u8)]
+pub enum CharacterEncoding {
+  Raw = 0x1,
+  Latin1 = 0x6,
+  ASCII = 0x7,
+  UTF8 = 0x8,
+  UTF16 = 0x10,
+  UTF32 = 0x20
+}
+
+impl CharacterEncoding {
+  pub fn new(input: u8) -> Result<Self, StegError> {
+    match input {
+      0x1 => Ok(CharacterEncoding::Raw),
+      0x6 => Ok(CharacterEncoding::Latin1),
+      0x7 => Ok(CharacterEncoding::ASCII),
+      0x8 => Ok(CharacterEncoding::UTF8),
+      0x10 => Ok(CharacterEncoding::UTF16),
+      0x20 => Ok(CharacterEncoding::UTF32),
+      _ => Err(StegError::UnknownEncodingTag(input))
+    }
+  }
+
+  pub fn to_bit_value(&self) -> u8 {
+    match self {
+      CharacterEncoding::Raw => 0x1,
+      CharacterEncoding::Latin1 => 0x6,
+      CharacterEncoding::ASCII => 0x7,
+      CharacterEncoding::UTF8 => 0x8,
+      CharacterEncoding::UTF16 => 0x10,
+      CharacterEncoding::UTF32 => 0x20
+    }
+  }
+}
+
+impl fmt::Display for CharacterEncoding {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      CharacterEncoding::Raw => write!(f, "Raw"),
+      CharacterEncoding::Latin1 => write!(f, "Latin1"),
+      CharacterEncoding::ASCII => write!(f, "ASCII"),
+      CharacterEncoding::UTF8 => write!(f, "UTF8"),
+      CharacterEncoding::UTF16 => write!(f, "UTF16"),
+      CharacterEncoding::UTF32 => write!(f, "UTF32")
+    }
+  }
+}
+
+/// What `Decoder::decode` hands back: text for the character-encoding tags,
+/// or the raw bytes as-is when the header carries `CharacterEncoding::Raw`
+/// (e.g. a serialized protobuf message embedded by the caller).
+#[derive(Debug, PartialEq)]
+pub enum Payload {
+  Text(String),
+  Bytes(Vec<u8>)
+}
+
+/// Calculate the total number of bits available for encoding in the image.
+pub fn calculate_capacity(image: &RgbaImage, config: &EncodeConfig) -> usize {
+  let channels_num = if config.ignore_alpha { 3 } else { 4 };
+  image.width() as usize * image.height() as usize * channels_num
+}
+
+pub fn calculate_bits_of_encoded_string(s: &str) -> (CharacterEncoding, u32) {
+  let mut max_code_point: u32 = 0u32;
+  for c in s.chars() {
+    let code_point: u32 = c as u32;
+    if code_point > max_code_point {
+      max_code_point = code_point;
+    }
+  }
+
+  if max_code_point <= 0x7F {
+    // Can be encoded in ASCII
+    return (CharacterEncoding::ASCII, s.len() as u32 * 8);
+  }
+
+  if max_code_point <= 0xFF {
+    // Western-European text dominated by accented Latin-1 characters packs
+    // into a single byte per char instead of the 16 bits UTF-8/16 would cost.
+    return (CharacterEncoding::Latin1, s.chars().count() as u32 * 8);
+  }
+
+  let utf8_bits: u32 = s.chars().map(|c| {
+    let code_point: u32 = c as u32;
+    if code_point <= 0x7F { 8 } // 1 byte
+    else if code_point <= 0x7FF { 16 } // 2 bytes
+    else if code_point <= 0xFFFF { 24 } // 3 bytes
+    else { 32 } // 4 bytes for characters beyond the BMP
+  }).sum();
+
+  // UTF-16 code units: 1 per BMP char, 2 per astral char (surrogate pair),
+  // plus a leading BOM unit that records byte order.
+  let utf16_code_units: u32 = 1 + s.chars()
+    .map(|c| if c as u32 > 0xFFFF { 2 } else { 1 })
+    .sum::<u32>();
+  let utf16_bits = utf16_code_units * 16;
+
+  let utf32_bits = s.chars().count() as u32 * 32;
+
+  if max_code_point <= 0xFFFF {
+    // No surrogate pairs needed; pick whichever of UTF-8/UTF-16 is smaller.
+    if utf8_bits <= utf16_bits { (CharacterEncoding::UTF8, utf8_bits) }
+    else { (CharacterEncoding::UTF16, utf16_bits) }
+  } else if utf16_bits < utf8_bits && utf16_bits < utf32_bits {
+    (CharacterEncoding::UTF16, utf16_bits)
+  } else if utf8_bits < utf32_bits {
+    (CharacterEncoding::UTF8, utf8_bits)
+  } else {
+    (CharacterEncoding::UTF32, utf32_bits)
+  }
+}
+
+/// Same as `calculate_bits_of_encoded_string`, but accounts for the
+/// automatic DEFLATE compression `Encoder::encode` applies: returns the
+/// number of bits that will actually be embedded, so capacity pre-checks
+/// (e.g. the CLI's) aren't overly conservative for compressible text.
+pub fn calculate_effective_bits(message: &str) -> (CharacterEncoding, u32) {
+  let (encoding, _) = calculate_bits_of_encoded_string(message);
+  let raw = message_bytes_for_encoding(message, &encoding);
+  let compressed = compress(&raw);
+  let payload_len = compressed.len().min(raw.len());
+
+  (encoding, payload_len as u32 * 8)
+}
+
+/// Number of bytes `Encoder::encode_framed` would spend on the length
+/// varint for a payload of `payload_bits` bits. Capacity pre-checks (e.g.
+/// the CLI's) need this to size the header correctly instead of assuming
+/// the old fixed 32-bit length field.
+pub fn varint_len(payload_bits: u32) -> usize {
+  encode_length_varint(payload_bits).len()
+}
+
+/// Total header + payload size, in bits, that `Encoder::encode_framed` will
+/// spend embedding a payload of `payload_bits` bits: the 8-bit tag, the
+/// variable-width length varint, the 32-bit CRC, and the payload itself.
+/// Capacity pre-checks across the CLI and the Neon addon should call this
+/// instead of re-deriving the same header arithmetic independently.
+pub fn framed_header_bits(payload_bits: u32) -> u32 {
+  8 + (varint_len(payload_bits) * 8) as u32 + 32 + payload_bits
+}
+
+/// Same idea as `calculate_effective_bits`, for a raw byte payload (e.g. a
+/// serialized protobuf `Session`) embedded via `Encoder::encode_bytes`
+/// rather than one of the text tiers.
+pub fn calculate_effective_bits_of_bytes(payload: &[u8]) -> u32 {
+  let compressed = compress(payload);
+  compressed.len().min(payload.len()) as u32 * 8
+}
+
+/// Materialize the byte buffer that gets embedded (before the optional
+/// compression stage) for a message already assigned to `encoding` by
+/// `calculate_bits_of_encoded_string`.
+fn message_bytes_for_encoding(message: &str, encoding: &CharacterEncoding) -> Vec<u8> {
+  match encoding {
+    CharacterEncoding::Raw => message.as_bytes().to_vec(),
+    CharacterEncoding::ASCII | CharacterEncoding::UTF8 => message.as_bytes().to_vec(),
+    CharacterEncoding::Latin1 => message.chars().map(|c| c as u8).collect(),
+    CharacterEncoding::UTF16 => {
+      // Leading BOM unit records byte order; honored back out in `Decoder::decode`.
+      let units = std::iter::once(0xFEFFu16).chain(message.chars().flat_map(|c| {
+        let code_point = c as u32;
+
+        if code_point > 0xFFFF {
+          let adjusted = code_point - 0x10000;
+          let high = 0xD800 | (adjusted >> 10);
+          let low = 0xDC00 | (adjusted & 0x3FF);
+          vec![high as u16, low as u16]
+        } else {
+          vec![code_point as u16]
+        }
+      }));
+
+      units.flat_map(|unit| unit.to_be_bytes()).collect()
+    },
+    CharacterEncoding::UTF32 => message.chars().flat_map(|c| (c as u32).to_be_bytes()).collect()
+  }
+}
+
+/// Run `bytes` through DEFLATE (zlib framing); used to shrink the payload
+/// before embedding when that actually wins. Compressing an in-memory
+/// `Vec<u8>` target cannot fail, so an I/O error here would indicate a bug.
+fn compress(bytes: &[u8]) -> Vec<u8> {
+  let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+  encoder.write_all(bytes).expect("compressing into an in-memory buffer cannot fail");
+  encoder.finish().expect("compressing into an in-memory buffer cannot fail")
+}
+
+/// Inverse of `compress`; returns `StegError::DecompressionFailed` instead
+/// of panicking when the stream is corrupt (e.g. a tampered or truncated
+/// carrier image).
+fn decompress(bytes: &[u8]) -> Result<Vec<u8>, StegError> {
+  let mut decoder = ZlibDecoder::new(bytes);
+  let mut out = Vec::new();
+  decoder.read_to_end(&mut out).map_err(|err| StegError::DecompressionFailed(err.to_string()))?;
+
+  Ok(out)
+}
+
+/// Encode `value` as a base-128 varint (the same LEB128-style scheme prost
+/// uses for protobuf lengths): 7 payload bits per byte, least-significant
+/// group first, with the continuation bit (0x80) set on every group but
+/// the last. At most 5 groups for a `u32`.
+fn encode_length_varint(mut value: u32) -> Vec<u8> {
+  let mut groups = Vec::new();
+
+  loop {
+    let byte = (value & 0x7F) as u8;
+    value >>= 7;
+
+    if value == 0 {
+      groups.push(byte);
+      break;
+    }
+
+    groups.push(byte | 0x80);
+  }
+
+  groups
+}
+
+fn to_binary_chunks(text: &str, chunk_size: usize) -> String {
+  text
+    .as_bytes()
+    .chunks(chunk_size)
+    .map(std::str::from_utf8)
+    .filter_map(Result::ok)
+    .collect::<Vec<&str>>()
+    .join(" ")
+}
+
+/// Build (once) the 256-entry lookup table for the reflected CRC-32 used to
+/// detect truncated or recompressed carrier images.
+fn crc32_table() -> &'static [u32; 256] {
+  static TABLE: OnceLock<[u32; 256]> = OnceLock::new();
+  TABLE.get_or_init(|| {
+    let mut table = [0u32; 256];
+    for n in 0..256u32 {
+      let mut c = n;
+      for _ in 0..8 {
+        c = if c & 1 != 0 { 0xEDB8_8320 ^ (c >> 1) } else { c >> 1 };
+      }
+      table[n as usize] = c;
+    }
+    table
+  })
+}
+
+/// Standard reflected CRC-32 (poly 0xEDB88320) over raw message bytes.
+pub fn crc32(bytes: &[u8]) -> u32 {
+  let table = crc32_table();
+  let mut crc = 0xFFFF_FFFFu32;
+  for &b in bytes {
+    crc = table[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8);
+  }
+  crc ^ 0xFFFF_FFFF
+}
+
+/// Recompute the CRC-32 over the reassembled payload bytes and compare it
+/// against the one stored in the header, returning `StegError::CrcMismatch`
+/// instead of silently handing back corrupt data on mismatch.
+fn verify_crc(bytes: &[u8], stored_crc: u32) -> Result<(), StegError> {
+  let computed_crc = crc32(bytes);
+
+  if computed_crc != stored_crc {
+    return Err(StegError::CrcMismatch { crc_val: stored_crc, crc_sum: computed_crc });
+  }
+
+  Ok(())
+}
+
+/// Iterates the bits (MSB-first) of a byte buffer via pure arithmetic,
+/// without allocating an intermediate string or `Vec<char>` per element.
+/// Always borrows the buffer it walks; callers own any transcoded or
+/// compressed bytes.
+struct BitWriter<'a> {
+  bytes: Cow<'a, [u8]>,
+  byte_index: usize,
+  bit_index: u8
+}
+
+impl<'a> BitWriter<'a> {
+  fn new(bytes: &'a [u8]) -> Self {
+    BitWriter { bytes: Cow::Borrowed(bytes), byte_index: 0, bit_index: 0 }
+  }
+}
+
+impl<'a> Iterator for BitWriter<'a> {
+  type Item = u8;
+
+  fn next(&mut self) -> Option<u8> {
+    let byte = *self.bytes.get(self.byte_index)?;
+    let bit = (byte >> (7 - self.bit_index)) & 1;
+
+    self.bit_index += 1;
+    if self.bit_index == 8 {
+      self.bit_index = 0;
+      self.byte_index += 1;
+    }
+
+    Some(bit)
+  }
+}
+
+/// Accumulates bits pushed one at a time (the LSB of each carrier channel)
+/// into whole bytes in fixed-size windows, instead of growing a `Vec<u8>`
+/// with one element per bit.
+struct BitReader {
+  bytes: Vec<u8>,
+  acc: u8,
+  acc_len: u8
+}
+
+impl BitReader {
+  fn new() -> Self {
+    BitReader { bytes: Vec::new(), acc: 0, acc_len: 0 }
+  }
+
+  fn push_bit(&mut self, bit: u8) {
+    self.acc = (self.acc << 1) | (bit & 1);
+    self.acc_len += 1;
+
+    if self.acc_len == 8 {
+      self.bytes.push(self.acc);
+      self.acc = 0;
+      self.acc_len = 0;
+    }
+  }
+
+  fn bytes(&self) -> &[u8] {
+    &self.bytes
+  }
+}
+
+/// Writes a header (encoding tag (+ compressed flag) + length + CRC-32)
+/// followed by the message payload into the least significant bit of each
+/// carrier channel.
+pub struct Encoder {
+  pub config: EncodeConfig
+}
+
+impl Encoder {
+  pub fn new(config: EncodeConfig) -> Self {
+    Encoder { config }
+  }
+
+  pub fn encode(&self, img: &RgbaImage, message: &str, encoding_input: CharacterEncoding) -> Result<RgbaImage, StegError> {
+    let (encoding, _) = calculate_bits_of_encoded_string(message);
+
+    if encoding.to_string() != encoding_input.to_string() {
+      return Err(StegError::EncodingMismatch);
+    }
+
+    let raw_bytes = message_bytes_for_encoding(message, &encoding);
+    let crc = crc32(message.as_bytes());
+
+    self.encode_framed(img, encoding.to_bit_value(), &raw_bytes, crc)
+  }
+
+  /// Embeds an arbitrary byte payload (e.g. a serialized protobuf message)
+  /// tagged with `CharacterEncoding::Raw` instead of one of the text tiers.
+  pub fn encode_bytes(&self, img: &RgbaImage, payload: &[u8]) -> Result<RgbaImage, StegError> {
+    let crc = crc32(payload);
+
+    self.encode_framed(img, CharacterEncoding::Raw.to_bit_value(), payload, crc)
+  }
+
+  /// Shared framing used by both `encode` and `encode_bytes`: compresses
+  /// `raw_bytes` when that wins, writes the header (tag byte, with the
+  /// `COMPRESSED_FLAG` bit set when compression was used, + a varint length
+  /// + CRC-32) and then the chosen payload into the least significant bit
+  /// of each carrier channel.
+  fn encode_framed(&self, img: &RgbaImage, tag: u8, raw_bytes: &[u8], crc: u32) -> Result<RgbaImage, StegError> {
+    let config = &self.config;
+    let mut encoded_img = img.clone();
+
+    let compressed = compress(raw_bytes);
+    let (tag, payload_bytes): (u8, Cow<[u8]>) = if compressed.len() < raw_bytes.len() {
+      (tag | COMPRESSED_FLAG, Cow::Owned(compressed))
+    } else {
+      (tag, Cow::Borrowed(raw_bytes))
+    };
+
+    let message_size = payload_bytes.len() as u32 * 8;
+
+    let encoding_byte = [tag];
+    let size_bytes = encode_length_varint(message_size);
+    let crc_bytes = crc.to_be_bytes();
+
+    let encoding_bits = format!("{:08b}", tag);
+
+    let mut encoding_bit_iter = BitWriter::new(&encoding_byte);
+    let mut size_bit_iter = BitWriter::new(&size_bytes);
+    let mut crc_bit_iter = BitWriter::new(&crc_bytes);
+    let mut payload_bit_iter = BitWriter::new(payload_bytes.as_ref());
+
+    let needed = 8 + size_bytes.len() * 8 + 32 + message_size as usize;
+    let available = calculate_capacity(img, config);
+    if needed > available {
+      return Err(StegError::CapacityExceeded { needed, available });
+    }
+
+    if config.debug {
+      println!("\n[Encoder] Tag byte (incl. compressed flag):\n  - Hexadecimal = 0x{:x}\n  - Binary = \"{}\"",
+        tag, to_binary_chunks(&encoding_bits, 4));
+      println!("[Encoder] Payload size after compression check ({} raw bytes -> {} embedded bytes):\n  - Decimal = {}\n  - Varint = {:02x?} ({} group(s))\n",
+        raw_bytes.len(), payload_bytes.len(), message_size, size_bytes, size_bytes.len());
+    }
+
+    let mut iter_chain = || {
+      encoding_bit_iter.next()
+        .or_else(|| size_bit_iter.next())
+        .or_else(|| crc_bit_iter.next())
+        .or_else(|| payload_bit_iter.next())
+    };
+
+    for (x, y, pixel) in encoded_img.enumerate_pixels_mut() {
+      // White pixels often correspond to background,
+      // and should be left untouched if possible.
+      // Usually not a problem,
+      // but I want to make this example as clean as possible.
+      let is_white: bool = pixel.channels()[0..3] == [255, 255, 255];
+      let is_black: bool = pixel.channels()[0..3] == [0, 0, 0];
+
+      if config.debug {
+        println!("[(Pixel at ({}, {})) == {:?}] White: {} Black: {}", x, y, pixel,
+          if is_white { "yes" } else { "no" }, if is_black { "yes" } else { "no" });
+      }
+
+      if (config.ignore_white_pixels && is_white) || (config.ignore_black_pixels && is_black) {
+        continue;
+      }
+
+      let channel_range = if config.ignore_alpha { 0..3 } else { 0..4 };
+      for i in channel_range {
+        if let Some(bit) = iter_chain() {
+          pixel.0[i] &= 0xFE; // Clear the least significant bit
+
+          if config.debug {
+            println!("  Channel({}) [{} -> {}]. Bit \"{}\"", Channel::new(i).expect("channel index is always in range").name(), pixel.0[i], pixel.0[i] | bit, bit);
+          }
+
+          pixel.0[i] |= bit; // Set the least significant bit to the message bit
+        } else {
+          // Stop if there are no more bits to encode
+          return Ok(encoded_img);
+        }
+      }
+    }
+
+    Ok(encoded_img)
+  }
+}
+
+/// Walks pixels' least significant bits, reassembles the header and payload,
+/// and verifies the CRC-32 before returning the message.
+pub struct Decoder {
+  pub config: EncodeConfig
+}
+
+impl Decoder {
+  pub fn new(config: EncodeConfig) -> Self {
+    Decoder { config }
+  }
+
+  pub fn decode(&self, img: &RgbaImage) -> Result<Payload, StegError> {
+    let config = &self.config;
+    let mut bit_reader = BitReader::new();
+    let mut header: Option<(CharacterEncoding, bool, u32, u32)> = None;
+
+    // Header framing, parsed one byte at a time as `bit_reader` fills in:
+    // tag(1) + length varint(1-5) + crc(4), byte-aligned throughout.
+    // `header_len` is unknown up front since the varint is variable-width.
+    let mut consumed = 0usize;
+    let mut tag_byte: Option<u8> = None;
+    let mut length_value: u32 = 0;
+    let mut length_shift: u32 = 0;
+    let mut length_groups: u8 = 0;
+    let mut length: Option<u32> = None;
+    let mut crc_bytes: Vec<u8> = Vec::with_capacity(4);
+
+    for pixel in img.pixels() {
+      let is_white: bool = pixel.channels()[0..3] == [255, 255, 255];
+      let is_black: bool = pixel.channels()[0..3] == [0, 0, 0];
+
+      if config.ignore_white_pixels && is_white {
+        continue;
+      }
+
+      if config.ignore_black_pixels && is_black {
+        continue;
+      }
+
+      let channel_range = if config.ignore_alpha { 0..3 } else { 0..4 };
+      for i in channel_range {
+        let bit: u8 = pixel.0[i] & 0x01; // Isolate the least significant bit
+
+        if config.debug {
+          println!("  Channel({}) [{} -> {}]. Bit \"{}\"", Channel::new(i)?.name(), pixel.0[i], bit, bit);
+        }
+
+        bit_reader.push_bit(bit);
+        let bytes = bit_reader.bytes();
+
+        // Feed newly-completed bytes into the header state machine until
+        // the tag, the varint length and the CRC are all known. The tag
+        // byte carries both the `CharacterEncoding` and, in its top bit,
+        // the `COMPRESSED_FLAG`.
+        while header.is_none() && bytes.len() > consumed {
+          let byte = bytes[consumed];
+          consumed += 1;
+
+          if tag_byte.is_none() {
+            tag_byte = Some(byte);
+            continue;
+          }
+
+          if length.is_none() {
+            length_groups += 1;
+            if length_groups > 5 {
+              return Err(StegError::VarintOverflow);
+            }
+
+            length_value |= ((byte & 0x7F) as u32) << length_shift;
+            length_shift += 7;
+
+            if byte & 0x80 == 0 {
+              length = Some(length_value);
+            }
+
+            continue;
+          }
+
+          crc_bytes.push(byte);
+          if crc_bytes.len() == 4 {
+            let compressed = tag_byte.unwrap() & COMPRESSED_FLAG != 0;
+            let encoding = CharacterEncoding::new(tag_byte.unwrap() & !COMPRESSED_FLAG)?;
+            let message_length = length.unwrap();
+            let message_crc = u32::from_be_bytes([crc_bytes[0], crc_bytes[1], crc_bytes[2], crc_bytes[3]]);
+
+            if config.debug {
+              println!("Message Length: {:?}", message_length);
+              println!("Encoding: {:?} (compressed: {})", encoding.to_string(), compressed);
+              println!("Stored CRC-32: 0x{:08x}", message_crc);
+            }
+
+            header = Some((encoding, compressed, message_length, message_crc));
+          }
+        }
+
+        if let Some((encoding, compressed, message_length, stored_crc)) = &header {
+          let payload_len = *message_length as usize / 8;
+          let header_len = consumed;
+
+          if bytes.len() == header_len + payload_len {
+            let framed_payload = &bytes[header_len..];
+            let payload: Cow<[u8]> = if *compressed {
+              Cow::Owned(decompress(framed_payload)?)
+            } else {
+              Cow::Borrowed(framed_payload)
+            };
+            let payload = payload.as_ref();
+
+            if encoding.to_bit_value() == CharacterEncoding::Raw.to_bit_value() {
+              verify_crc(payload, *stored_crc)?;
+              return Ok(Payload::Bytes(payload.to_vec()));
+            }
+
+            let message = if encoding.to_bit_value() == CharacterEncoding::ASCII.to_bit_value() {
+              payload.iter().map(|&b| b as char).collect::<String>()
+            } else if encoding.to_bit_value() == CharacterEncoding::Latin1.to_bit_value() {
+              payload.iter().map(|&b| char::from(b)).collect::<String>()
+            } else if encoding.to_bit_value() == CharacterEncoding::UTF8.to_bit_value() {
+              String::from_utf8(payload.to_vec()).map_err(|_| StegError::InvalidUtf8)?
+            } else if encoding.to_bit_value() == CharacterEncoding::UTF16.to_bit_value() {
+              let units: Vec<u16> = payload.chunks_exact(2)
+                .map(|chunk| u16::from_be_bytes([chunk[0], chunk[1]]))
+                .collect();
+
+              // The first code unit is a BOM; honor whichever byte order it records.
+              let little_endian = units.first() == Some(&0xFFFE);
+              let message_vec: Vec<u16> = units[1..].iter()
+                .map(|&unit| if little_endian { unit.swap_bytes() } else { unit })
+                .collect();
+
+              String::from_utf16(&message_vec).map_err(|_| StegError::InvalidUtf16)?
+            } else if encoding.to_bit_value() == CharacterEncoding::UTF32.to_bit_value() {
+              payload.chunks_exact(4)
+                .map(|chunk| {
+                  let code_point = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+                  std::char::from_u32(code_point).ok_or(StegError::InvalidUtf32)
+                })
+                .collect::<Result<String, StegError>>()?
+            } else {
+              String::new()
+            };
+
+            verify_crc(message.as_bytes(), *stored_crc)?;
+            return Ok(Payload::Text(message));
+          }
+        }
+      }
+    }
+
+    // Fell off the end of the image before the header or payload was
+    // fully read.
+    Err(StegError::TruncatedPayload)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn test_config() -> EncodeConfig {
+    EncodeConfig { ignore_alpha: false, ignore_white_pixels: false, ignore_black_pixels: false, debug: false }
+  }
+
+  fn carrier_image(width: u32, height: u32) -> RgbaImage {
+    RgbaImage::from_pixel(width, height, image::Rgba([10, 20, 30, 255]))
+  }
+
+  /// Writes `bytes` MSB-first into the least significant bit of every
+  /// channel of a freshly allocated carrier image, in the same order
+  /// `Encoder`/`Decoder` use. Lets a test craft header bytes `Encoder`
+  /// itself would never produce (e.g. a runaway varint).
+  fn image_from_bits(bytes: &[u8], width: u32, height: u32) -> RgbaImage {
+    let mut img = carrier_image(width, height);
+    let mut bit_iter = bytes.iter().flat_map(|&byte| (0..8).rev().map(move |shift| (byte >> shift) & 1));
+
+    'pixels: for pixel in img.pixels_mut() {
+      for i in 0..4 {
+        match bit_iter.next() {
+          Some(bit) => {
+            pixel.0[i] &= 0xFE;
+            pixel.0[i] |= bit;
+          },
+          None => break 'pixels
+        }
+      }
+    }
+
+    img
+  }
+
+  #[test]
+  fn test_encode_decode_round_trip_ascii() {
+    let img = carrier_image(40, 40);
+    let message = "Hello, steganography!";
+
+    let encoded = Encoder::new(test_config()).encode(&img, message, CharacterEncoding::ASCII).unwrap();
+    let decoded = Decoder::new(test_config()).decode(&encoded).unwrap();
+
+    assert_eq!(decoded, Payload::Text(message.to_string()));
+  }
+
+  #[test]
+  fn test_encode_decode_round_trip_raw_bytes() {
+    let img = carrier_image(40, 40);
+    let payload = vec![0u8, 1, 2, 3, 255, 128, 64];
+
+    let encoded = Encoder::new(test_config()).encode_bytes(&img, &payload).unwrap();
+    let decoded = Decoder::new(test_config()).decode(&encoded).unwrap();
+
+    assert_eq!(decoded, Payload::Bytes(payload));
+  }
+
+  #[test]
+  fn test_compression_shrinks_repetitive_payload_and_round_trips() {
+    let img = carrier_image(200, 200);
+    let message = "a".repeat(200);
+
+    // Sanity-check the premise: a highly repetitive payload really does
+    // compress, so the encoder's automatic "only if it wins" choice has
+    // something to pick.
+    let compressed = compress(message.as_bytes());
+    assert!(compressed.len() < message.len(), "repeated payload should compress smaller than raw");
+
+    let encoded = Encoder::new(test_config()).encode(&img, &message, CharacterEncoding::ASCII).unwrap();
+    let decoded = Decoder::new(test_config()).decode(&encoded).unwrap();
+
+    assert_eq!(decoded, Payload::Text(message));
+  }
+
+  #[test]
+  fn test_decode_detects_tampered_payload_via_crc() {
+    let img = carrier_image(40, 40);
+    let message = "tamper me";
+
+    let mut encoded = Encoder::new(test_config()).encode(&img, message, CharacterEncoding::ASCII).unwrap();
+
+    // Flip the least significant bit of a pixel inside the payload region
+    // to simulate a corrupted carrier image. The header (8-bit tag + 1-byte
+    // varint + 32-bit CRC = 48 bits) fills pixels 0..=11 at 4 bits each, so
+    // pixel 12 carries the first payload bit.
+    encoded.get_pixel_mut(12, 0).0[0] ^= 0x01;
+
+    let result = Decoder::new(test_config()).decode(&encoded);
+    assert!(matches!(result, Err(StegError::CrcMismatch { .. })));
+  }
+
+  #[test]
+  fn test_channel_new_rejects_out_of_range_index() {
+    assert!(matches!(Channel::new(4), Err(StegError::InvalidChannelIndex(4))));
+  }
+
+  #[test]
+  fn test_character_encoding_new_rejects_unknown_tag() {
+    assert!(matches!(CharacterEncoding::new(0xFF), Err(StegError::UnknownEncodingTag(0xFF))));
+  }
+
+  #[test]
+  fn test_decode_returns_truncated_payload_instead_of_guessing() {
+    // A single pixel has nowhere near enough bits for even the 8-bit tag
+    // byte, let alone the rest of the header. This used to return an empty
+    // string; it should now fail loudly instead.
+    let img = carrier_image(1, 1);
+    let result = Decoder::new(test_config()).decode(&img);
+    assert!(matches!(result, Err(StegError::TruncatedPayload)));
+  }
+
+  #[test]
+  fn test_varint_roundtrip_values() {
+    assert_eq!(encode_length_varint(0), vec![0x00]);
+    assert_eq!(encode_length_varint(127), vec![0x7F]);
+    assert_eq!(encode_length_varint(128), vec![0x80, 0x01]);
+    assert_eq!(encode_length_varint(300), vec![0xAC, 0x02]);
+  }
+
+  #[test]
+  fn test_decode_rejects_varint_longer_than_five_groups() {
+    // Tag byte + 6 continuation-flagged varint groups that never terminate
+    // + a CRC and a payload byte the decoder should never reach.
+    let mut bytes = vec![CharacterEncoding::ASCII.to_bit_value()];
+    bytes.extend(std::iter::repeat(0x80u8).take(6));
+    bytes.extend_from_slice(&[0, 0, 0, 0]);
+    bytes.push(0x41);
+
+    let encoded = image_from_bits(&bytes, 40, 40);
+    let result = Decoder::new(test_config()).decode(&encoded);
+
+    assert!(matches!(result, Err(StegError::VarintOverflow)));
+  }
+}